@@ -1,20 +1,57 @@
 //! Models representing database objects.
 
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::marker::PhantomData;
 
 extern crate ring;
-use self::ring::{digest, pbkdf2};
+use self::ring::{constant_time, digest, pbkdf2, signature};
+
+extern crate argon2;
+use self::argon2::{Algorithm, Argon2, Params, Version};
 
 static DIGEST_ALG: &'static digest::Algorithm = &digest::SHA256;
 const CREDENTIAL_LEN: usize = digest::SHA256_OUTPUT_LEN;
-const HASH_ITERATIONS: u32 = 100_000;
-const SALT_LEN: usize = CREDENTIAL_LEN;
-/// A password hash.
-/// Generated using the PBKDF2 algorithm.
-pub type Credential = [u8; CREDENTIAL_LEN];
-/// Password salt.
-/// Random bytes to increase hash security.
-pub type Salt = [u8; SALT_LEN];
+
+/// Baseline Argon2id memory cost, in KiB (~19 MiB).
+const ARGON2_M_COST: u32 = 19 * 1024;
+/// Baseline Argon2id number of passes.
+const ARGON2_T_COST: u32 = 2;
+/// Baseline Argon2id degree of parallelism.
+const ARGON2_P_COST: u32 = 1;
+
+/// A versioned password hash.
+///
+/// Each variant stores its own algorithm parameters inline so the hashing
+/// scheme can evolve without breaking existing hashes. New credentials use
+/// [`Credential::new_argon2id`]; legacy `Pbkdf2` credentials remain verifiable,
+/// and a successful login against one returns
+/// [`VerifyOutcome::VerifiedNeedsRehash`] so the caller can replace it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// A legacy PBKDF2-SHA256 hash.
+    Pbkdf2 {
+        /// Number of PBKDF2 iterations.
+        iterations: u32,
+        /// Salt bytes.
+        salt: Vec<u8>,
+        /// Derived hash bytes.
+        hash: Vec<u8>,
+    },
+    /// A memory-hard Argon2id hash.
+    Argon2id {
+        /// Memory cost, in KiB.
+        m_cost: u32,
+        /// Number of passes.
+        t_cost: u32,
+        /// Degree of parallelism.
+        p_cost: u32,
+        /// Salt bytes.
+        salt: Vec<u8>,
+        /// Derived hash bytes.
+        hash: Vec<u8>,
+    },
+}
 
 /// A timestamp from the system time.
 /// Represents the number of seconds since the Unix epoch.
@@ -23,7 +60,7 @@ pub struct Timestamp(pub u64);
 
 /// An opaque (possible comparable) ID.
 /// Guaranteed to be ordered based on creation.
-#[derive(Debug, Copy, Clone, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct OpaqueID<T>(u64, PhantomData<T>);
 
 /// A user ID.
@@ -32,6 +69,10 @@ pub type UserID = OpaqueID<User>;
 pub type RoomID = OpaqueID<Room>;
 /// A message ID.
 pub type MessageID = OpaqueID<Message>;
+/// A report ID.
+pub type ReportID = OpaqueID<Report>;
+/// A device ID.
+pub type DeviceID = OpaqueID<Device>;
 
 /// A user.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -42,12 +83,75 @@ pub struct User {
     pub email: String,
     /// Password hash.
     pub credential: Credential,
-    /// Salt used for this user's password.
-    pub salt: Salt,
     /// User name.
     pub name: String,
 }
 
+/// The long-lived public keys identifying a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceKeys {
+    /// Ed25519 signing public key.
+    pub ed25519: Vec<u8>,
+    /// Curve25519 identity public key.
+    pub curve25519: Vec<u8>,
+}
+
+/// Algorithm tag for a key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// Ed25519 signing key.
+    Ed25519,
+    /// Signed Curve25519 one-time key.
+    SignedCurve25519,
+}
+
+/// A claimable prekey published by a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneTimeKey {
+    /// Algorithm this key is for.
+    pub algorithm: KeyAlgorithm,
+    /// Public key bytes.
+    pub key: Vec<u8>,
+}
+
+/// A device belonging to a user, carrying its end-to-end encryption keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// Device ID.
+    pub id: DeviceID,
+    /// User that owns the device.
+    pub user_id: UserID,
+    /// Human-readable device name.
+    pub display_name: Option<String>,
+    /// Long-lived identity and signing keys.
+    pub device_keys: DeviceKeys,
+    /// Unclaimed one-time keys published by this device.
+    pub one_time_keys: Vec<OneTimeKey>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The signature did not verify against the device's signing key.
+    InvalidSignature,
+}
+
+// device key operations
+impl Device {
+    /// Claims a one-time key, removing and returning it so no two callers can
+    /// be handed the same prekey. Returns `None` if none remain.
+    fn claim_one_time_key(&mut self) -> Option<OneTimeKey> {
+        self.one_time_keys.pop()
+    }
+
+    /// Verifies an Ed25519 signature over `message` against this device's
+    /// signing key.
+    fn verify_signature(&self, message: &[u8], sig: &[u8]) -> Result<(), SignatureError> {
+        let key = signature::UnparsedPublicKey::new(&signature::ED25519, &self.device_keys.ed25519);
+        key.verify(message, sig)
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+}
+
 /// Room visibility.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RoomVisibility {
@@ -55,6 +159,8 @@ pub enum RoomVisibility {
     Public,
     /// Not publicly visible; moreover, not joinable unless invited.
     Private,
+    /// Not publicly visible, but non-members may knock to request an invite.
+    Knock,
 }
 
 /// A room.
@@ -68,6 +174,99 @@ pub struct Room {
     pub visibility: RoomVisibility,
 }
 
+/// Membership state of a user within a room.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MembershipState {
+    /// Invited to the room but not yet joined.
+    Invite,
+    /// An active member of the room.
+    Join,
+    /// Previously a member, now departed.
+    Leave,
+    /// Barred from the room.
+    Ban,
+    /// Requesting an invite to a room that accepts knocks.
+    Knock,
+}
+
+/// A user's membership in a room.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Membership {
+    /// User the membership belongs to.
+    pub user_id: UserID,
+    /// Room the membership applies to.
+    pub room_id: RoomID,
+    /// Current membership state.
+    pub state: MembershipState,
+    /// Date the state was last changed.
+    pub since: Timestamp,
+}
+
+/// Minimum power level required to ban a user.
+const MIN_BAN_POWER: i64 = 50;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MembershipError {
+    /// The acting user lacks the power required for the transition.
+    InsufficientPower,
+    /// The requested transition is not allowed from the current state.
+    IllegalTransition,
+}
+
+impl MembershipState {
+    /// Whether this state represents someone currently in the room,
+    /// i.e. invited or joined.
+    fn is_member(self) -> bool {
+        matches!(self, MembershipState::Invite | MembershipState::Join)
+    }
+}
+
+// membership state machine
+impl Membership {
+    /// Validates a transition to `to` by a user with `actor_power`, returning
+    /// the updated membership stamped with `since` on success.
+    ///
+    /// Enforces that bans require sufficient power and that knocks may only
+    /// come from non-members, rejecting illegal moves outright so callers
+    /// cannot construct impossible states.
+    fn transition(
+        &self,
+        to: MembershipState,
+        actor_power: i64,
+        since: Timestamp,
+    ) -> Result<Membership, MembershipError> {
+        use self::MembershipState::*;
+        let allowed = match to {
+            Ban => {
+                if actor_power < MIN_BAN_POWER {
+                    return Err(MembershipError::InsufficientPower);
+                }
+                true
+            }
+            Knock => !self.state.is_member() && self.state != Ban,
+            Invite => matches!(self.state, Leave | Knock),
+            Join => matches!(self.state, Invite | Knock | Leave),
+            Leave => {
+                // Unbanning (Ban -> Leave) is power-gated like the ban itself;
+                // every other outgoing Leave is always permitted.
+                if self.state == Ban && actor_power < MIN_BAN_POWER {
+                    return Err(MembershipError::InsufficientPower);
+                }
+                true
+            }
+        };
+        if !allowed {
+            return Err(MembershipError::IllegalTransition);
+        }
+        Ok(Membership {
+            user_id: self.user_id,
+            room_id: self.room_id,
+            state: to,
+            since,
+        })
+    }
+}
+
 /// A message.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
@@ -79,6 +278,10 @@ pub struct Message {
     pub user_id: UserID,
     /// Room ID.
     pub room_id: RoomID,
+    /// IDs of the events this one directly follows in the room DAG.
+    pub prev_events: Vec<MessageID>,
+    /// IDs of the events authorizing this one.
+    pub auth_events: Vec<MessageID>,
     /// Message data.
     pub data: MessageData,
 }
@@ -104,21 +307,374 @@ pub enum MessageData {
         /// Old message ID.
         edit_id: MessageID,
     },
+    /// A redaction of a message.
+    Redact {
+        /// ID of the message being redacted.
+        redact_id: MessageID,
+        /// Optional reason for the redaction.
+        reason: Option<String>,
+    },
     /// User join notification.
     Join,
     /// User leave notification.
     Leave,
 }
 
+// redaction
+impl Message {
+    /// Returns a redacted copy of this message.
+    ///
+    /// The copy preserves `id`, `date`, `user_id`, and `room_id` so that the
+    /// event graph stays intact, but strips any text content from `data`,
+    /// leaving only the structural references (edit and redaction targets).
+    fn redacted(&self) -> Message {
+        let data = match self.data {
+            MessageData::Message { .. } => MessageData::Message {
+                message: String::new(),
+            },
+            MessageData::DirectMessage { recipient, .. } => MessageData::DirectMessage {
+                message: String::new(),
+                recipient,
+            },
+            MessageData::Edit { edit_id, .. } => MessageData::Edit {
+                new_message: String::new(),
+                edit_id,
+            },
+            MessageData::Redact { redact_id, .. } => MessageData::Redact {
+                redact_id,
+                reason: None,
+            },
+            MessageData::Join => MessageData::Join,
+            MessageData::Leave => MessageData::Leave,
+        };
+        Message {
+            id: self.id,
+            date: self.date,
+            user_id: self.user_id,
+            room_id: self.room_id,
+            prev_events: self.prev_events.clone(),
+            auth_events: self.auth_events.clone(),
+            data,
+        }
+    }
+}
+
+/// An abuse report filed against a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Report ID.
+    pub id: ReportID,
+    /// User who filed the report.
+    pub reporter: UserID,
+    /// Message being reported.
+    pub message_id: MessageID,
+    /// Room the reported message belongs to.
+    pub room_id: RoomID,
+    /// Reason given by the reporter.
+    pub reason: String,
+    /// Severity score, from -100 (most severe) to 0 (least severe).
+    pub score: i8,
+    /// Date the report was filed.
+    pub date: Timestamp,
+}
+
+/// Maximum length, in bytes, of a report reason.
+const MAX_REASON_LEN: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReportError {
+    /// Score was outside the allowed range of -100..=0.
+    ScoreOutOfRange,
+    /// Reason exceeded the maximum length.
+    ReasonTooLong,
+}
+
+// report validation
+impl Report {
+    /// Checks that this report upholds its invariants:
+    /// the score is within -100..=0 and the reason is not too long.
+    fn validate(&self) -> Result<(), ReportError> {
+        if !(-100..=0).contains(&self.score) {
+            return Err(ReportError::ScoreOutOfRange);
+        }
+        if self.reason.len() > MAX_REASON_LEN {
+            return Err(ReportError::ReasonTooLong);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PasswordError {
     IncorrectPassword
 }
 
+/// The result of a successful password verification.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    /// The stored credential already uses the current scheme.
+    Verified,
+    /// Verification succeeded against a legacy credential; the caller should
+    /// rehash the plaintext into a fresh Argon2id credential.
+    VerifiedNeedsRehash,
+}
+
+// credential hashing and verification
+impl Credential {
+    /// Derives a fresh Argon2id credential from `password` and `salt` using
+    /// the baseline cost parameters.
+    fn new_argon2id(password: &str, salt: Vec<u8>) -> Credential {
+        let hash = Self::derive_argon2id(
+            password,
+            &salt,
+            ARGON2_M_COST,
+            ARGON2_T_COST,
+            ARGON2_P_COST,
+        );
+        Credential::Argon2id {
+            m_cost: ARGON2_M_COST,
+            t_cost: ARGON2_T_COST,
+            p_cost: ARGON2_P_COST,
+            salt,
+            hash,
+        }
+    }
+
+    /// Verifies `password` against this credential.
+    ///
+    /// A legacy `Pbkdf2` credential that verifies yields
+    /// [`VerifyOutcome::VerifiedNeedsRehash`] to drive transparent migration.
+    fn verify(&self, password: &str) -> Result<VerifyOutcome, PasswordError> {
+        match *self {
+            Credential::Pbkdf2 {
+                iterations,
+                ref salt,
+                ref hash,
+            } => pbkdf2::verify(DIGEST_ALG, iterations, salt, password.as_bytes(), hash)
+                .map(|_| VerifyOutcome::VerifiedNeedsRehash)
+                .map_err(|_| PasswordError::IncorrectPassword),
+            Credential::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                ref salt,
+                ref hash,
+            } => {
+                let derived = Self::derive_argon2id(password, salt, m_cost, t_cost, p_cost);
+                constant_time::verify_slices_are_equal(&derived, hash)
+                    .map(|_| VerifyOutcome::Verified)
+                    .map_err(|_| PasswordError::IncorrectPassword)
+            }
+        }
+    }
+
+    /// Runs the Argon2id KDF, returning a hash the same length as a PBKDF2
+    /// digest so legacy and new credentials share an output size.
+    fn derive_argon2id(
+        password: &str,
+        salt: &[u8],
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    ) -> Vec<u8> {
+        let params = Params::new(m_cost, t_cost, p_cost, Some(CREDENTIAL_LEN))
+            .expect("valid Argon2id parameters");
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let mut out = vec![0u8; CREDENTIAL_LEN];
+        argon2
+            .hash_password_into(password.as_bytes(), salt, &mut out)
+            .expect("Argon2id derivation");
+        out
+    }
+}
+
+/// Presence status advertised by a user.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PresenceState {
+    /// Actively using the service.
+    Online,
+    /// Connected but idle.
+    Unavailable,
+    /// Not connected.
+    Offline,
+}
+
+/// An ephemeral data unit: transient signalling that is not persisted in the
+/// message log and has no [`OpaqueID`] of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edu {
+    /// A typing notification.
+    Typing {
+        /// User who is (or has stopped) typing.
+        user_id: UserID,
+        /// Room the notification applies to.
+        room_id: RoomID,
+        /// Whether the user is currently typing.
+        active: bool,
+        /// Time at which the notification should be considered stale.
+        timeout: Timestamp,
+    },
+    /// A read receipt.
+    Receipt {
+        /// User acknowledging the messages.
+        user_id: UserID,
+        /// Room the receipt applies to.
+        room_id: RoomID,
+        /// Most recent message the user has read.
+        up_to: MessageID,
+        /// Time the receipt was issued.
+        at: Timestamp,
+    },
+    /// A presence update.
+    Presence {
+        /// User whose presence changed.
+        user_id: UserID,
+        /// New presence status.
+        status: PresenceState,
+        /// Time the user was last active.
+        last_active: Timestamp,
+    },
+}
+
+impl Edu {
+    /// Whether this is a typing notice that is no longer active as of `now`,
+    /// either because typing stopped or the timeout has passed. Non-typing
+    /// units are never considered expired.
+    fn expired(&self, now: Timestamp) -> bool {
+        match *self {
+            Edu::Typing {
+                active, timeout, ..
+            } => !active || now >= timeout,
+            _ => false,
+        }
+    }
+}
+
+/// Folds a stream of ephemeral units into a per-user "last read" map, keyed by
+/// user and holding the `up_to` message from that user's most recent receipt.
+/// Non-receipt units are ignored.
+pub fn last_read<I>(edus: I) -> HashMap<UserID, MessageID>
+where
+    I: IntoIterator<Item = Edu>,
+{
+    let mut latest: HashMap<UserID, (Timestamp, MessageID)> = HashMap::new();
+    for edu in edus {
+        if let Edu::Receipt {
+            user_id, up_to, at, ..
+        } = edu
+        {
+            let entry = latest.entry(user_id).or_insert((at, up_to));
+            if at >= entry.0 {
+                *entry = (at, up_to);
+            }
+        }
+    }
+    latest
+        .into_iter()
+        .map(|(user, (_, up_to))| (user, up_to))
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OrderError {
+    /// The event graph contains a cycle; some events could not be ordered.
+    Cycle,
+}
+
+/// Tie-break key for mainline ordering.
+///
+/// Ordered so the greatest key is the event that should be emitted first:
+/// highest sender power, then earliest date, then smallest message ID.
+#[derive(PartialEq, Eq)]
+struct MainlineKey {
+    power: i64,
+    date: Timestamp,
+    id: MessageID,
+}
+
+impl Ord for MainlineKey {
+    fn cmp(&self, other: &MainlineKey) -> Ordering {
+        self.power
+            .cmp(&other.power)
+            .then_with(|| other.date.cmp(&self.date))
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for MainlineKey {
+    fn partial_cmp(&self, other: &MainlineKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Produces a deterministic linear order over an event DAG.
+///
+/// Performs a Kahn topological sort keyed on `MessageID`, breaking ties
+/// between simultaneously-eligible events by `(power_level desc, date asc,
+/// message_id asc)` via a binary heap. `prev_events` referencing events
+/// outside `events` are treated as already-emitted. The order is independent
+/// of input and hash-map iteration order, so it is stable across servers.
+/// A remaining nonzero in-degree (a cycle) surfaces as [`OrderError::Cycle`]
+/// rather than panicking or looping.
+pub fn order_events<F>(events: &[Message], power_level: F) -> Result<Vec<MessageID>, OrderError>
+where
+    F: Fn(UserID, RoomID) -> i64,
+{
+    let present: HashMap<MessageID, &Message> = events.iter().map(|m| (m.id, m)).collect();
+    let mut in_degree: HashMap<MessageID, usize> = HashMap::new();
+    let mut children: HashMap<MessageID, Vec<MessageID>> = HashMap::new();
+    for m in events {
+        let mut degree = 0;
+        for prev in &m.prev_events {
+            if present.contains_key(prev) {
+                degree += 1;
+                children.entry(*prev).or_insert_with(Vec::new).push(m.id);
+            }
+        }
+        in_degree.insert(m.id, degree);
+    }
+
+    let key_for = |m: &Message| MainlineKey {
+        power: power_level(m.user_id, m.room_id),
+        date: m.date,
+        id: m.id,
+    };
+
+    // Seed the heap in input order; the heap itself imposes a deterministic
+    // ordering, so this does not rely on hash-map iteration.
+    let mut heap: BinaryHeap<MainlineKey> = BinaryHeap::new();
+    for m in events {
+        if in_degree[&m.id] == 0 {
+            heap.push(key_for(m));
+        }
+    }
+
+    let mut order = Vec::with_capacity(events.len());
+    while let Some(MainlineKey { id, .. }) = heap.pop() {
+        order.push(id);
+        if let Some(kids) = children.get(&id) {
+            for kid in kids {
+                let degree = in_degree.get_mut(kid).expect("child has an in-degree entry");
+                *degree -= 1;
+                if *degree == 0 {
+                    heap.push(key_for(present[kid]));
+                }
+            }
+        }
+    }
+
+    if order.len() != events.len() {
+        return Err(OrderError::Cycle);
+    }
+    Ok(order)
+}
+
 // password verification
 impl User {
-    fn verify_password(&self, password: &str) -> Result<(), PasswordError> {
-        pbkdf2::verify(DIGEST_ALG, HASH_ITERATIONS, &self.salt, password.as_bytes(), &self.credential)
-            .map_err(|_| PasswordError::IncorrectPassword)
+    /// Verifies `password` against this user's stored credential.
+    ///
+    /// See [`Credential::verify`] for the rehash-on-login signal.
+    fn verify_password(&self, password: &str) -> Result<VerifyOutcome, PasswordError> {
+        self.credential.verify(password)
     }
 }